@@ -4,91 +4,345 @@
 /// https://blog.jcoglan.com/2017/07/06/introduction-to-parser-combinators/
 ///
 use regex::Regex;
+use std::any::Any;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use unicode_segmentation::UnicodeSegmentation;
 
+static NEXT_PARSER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Assigns a stable id to a newly constructed parser, used to key the
+/// packrat memo table by `(parser_id, offset)`.
+fn next_id() -> usize {
+    NEXT_PARSER_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Input is pre-segmented into grapheme clusters so `offset`, `peek` and
+/// `read` count user-perceived characters instead of bytes, which would
+/// otherwise panic on multibyte UTF-8 like emoji or accented letters.
 #[derive(Debug, PartialEq, Clone)]
 pub struct State {
-    s: String,
+    graphemes: Vec<String>,
     offset: usize,
 }
 
 impl State {
     pub fn new(s: String, offset: usize) -> Self {
-        Self { s, offset }
+        Self {
+            graphemes: s.graphemes(true).map(|g| g.to_string()).collect(),
+            offset,
+        }
     }
 
     fn peek(&self, n: usize) -> String {
-        if self.offset + n > self.s.len() {
+        if self.offset + n > self.graphemes.len() {
             String::new()
         } else {
-            self.s[self.offset..self.offset + n].to_string()
+            self.graphemes[self.offset..self.offset + n].concat()
         }
     }
 
     fn read(&self, n: usize) -> Self {
-        Self::new(self.s.to_string(), self.offset + n)
+        Self {
+            graphemes: self.graphemes.clone(),
+            offset: self.offset + n,
+        }
+    }
+
+    fn at_offset(&self, offset: usize) -> Self {
+        Self {
+            graphemes: self.graphemes.clone(),
+            offset,
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.graphemes.len()
+    }
+}
+
+/// The offset a parser got stuck at and what it was hoping to find there,
+/// e.g. `expected one of ["cookie", "0-9"] at offset 12`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParseError {
+    pub offset: usize,
+    pub expected: Vec<String>,
+}
+
+impl ParseError {
+    fn new(offset: usize, expected: Vec<String>) -> Self {
+        Self { offset, expected }
+    }
+
+    /// Keeps whichever error got furthest into the input, unioning
+    /// `expected` when both got equally far.
+    fn furthest(self, other: Self) -> Self {
+        if other.offset > self.offset {
+            other
+        } else if other.offset == self.offset {
+            let mut expected = self.expected;
+            expected.extend(other.expected);
+            Self::new(self.offset, expected)
+        } else {
+            self
+        }
+    }
+}
+
+/// Cache of `(parser_id, offset) -> result`, opt in via `parse_memo`. Since
+/// every combinator in this crate owns its children outright (there's no
+/// `Rc`/shared-node support), a given node can only be visited more than
+/// once at the same offset across separate top-level `parse_memo` calls on
+/// the same parser and the same input. Entries are not keyed by the input
+/// itself, so a table reused after the input changes returns results
+/// computed for the old input. It does not
+/// speed up a single descent through a nested `Alt`/`Seq`/`Rep` tree, since
+/// no two call paths within one parse can reach the same node.
+pub struct Memo {
+    table: HashMap<(usize, usize), Box<dyn Any>>,
+}
+
+impl Default for Memo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memo {
+    pub fn new() -> Self {
+        Self {
+            table: HashMap::new(),
+        }
+    }
+
+    fn get<O: Clone + 'static>(&self, key: (usize, usize)) -> Option<Result<(O, usize), ParseError>> {
+        self.table.get(&key).map(|entry| {
+            entry
+                .downcast_ref::<Result<(O, usize), ParseError>>()
+                .expect("memo entry type mismatch for parser id")
+                .clone()
+        })
+    }
+
+    fn insert<O: Clone + 'static>(&mut self, key: (usize, usize), value: Result<(O, usize), ParseError>) {
+        self.table.insert(key, Box::new(value));
     }
 }
 
 pub trait Parse {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)>;
+    // Outputs must be `Clone` so a packrat result can be stored in and
+    // returned from the memo table without reparsing.
+    type Output: Clone + 'static;
+
+    fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError>;
+
+    /// The stable id this parser was registered under, used to key the
+    /// packrat memo table.
+    fn id(&self) -> usize;
+
+    /// Same as `parse`, but checks the memo table for a cached result at
+    /// this `(id, offset)` before descending, and stores the result after.
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Self::Output, State), ParseError> {
+        let key = (self.id(), state.offset);
+        if let Some(cached) = memo.get::<Self::Output>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = self.parse(state);
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+
+    /// Sequences `self` then `next`, producing both outputs as a tuple.
+    fn then<Q>(self, next: Q) -> Box<Then<Self, Q>>
+    where
+        Self: Sized + 'static,
+        Q: Parse + 'static,
+    {
+        Box::new(Then::new(self, next))
+    }
+
+    /// Ordered choice between `self` and `alt`: tries `self` first, falling
+    /// back to `alt` only on failure.
+    fn or<Q>(self, alt: Q) -> Box<Alt<Self::Output>>
+    where
+        Self: Sized + 'static,
+        Q: Parse<Output = Self::Output> + 'static,
+    {
+        Box::new(Alt::new(vec![Box::new(self), Box::new(alt)]))
+    }
+
+    /// Zero-or-more repetitions of `self`.
+    fn many(self) -> Box<Rep<Self::Output>>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(Rep::new(Box::new(self), 0))
+    }
+
+    /// One-or-more repetitions of `self`.
+    fn many1(self) -> Box<Rep<Self::Output>>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(Rep::new(Box::new(self), 1))
+    }
+
+    /// Makes `self` optional, succeeding with `None` (without consuming
+    /// input) when `self` fails.
+    fn opt(self) -> Box<Opt<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(Opt::new(self))
+    }
+
+    /// Applies `f` to `self`'s output on success.
+    fn map<O, F>(self, f: F) -> Box<Map<Self, F, O>>
+    where
+        Self: Sized + 'static,
+        F: Fn(Self::Output) -> O,
+        O: Clone + 'static,
+    {
+        Box::new(Map::new(self, f))
+    }
+
+    /// Zero-width positive lookahead: asserts `self` matches here without
+    /// consuming any input.
+    fn peek(self) -> Box<Peek<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(Peek::new(self))
+    }
+
+    /// Zero-width negative lookahead: asserts `self` does *not* match here,
+    /// without consuming any input. `label` describes what was forbidden,
+    /// e.g. `"</"`, and is reported in `ParseError::expected` on failure.
+    fn not(self, label: impl Into<String>) -> Box<Not<Self>>
+    where
+        Self: Sized + 'static,
+    {
+        Box::new(Not::new(self, label))
+    }
+
+    /// Keeps `self`'s match only if `f` returns true for its output. `label`
+    /// describes what the predicate requires, e.g. `"digit >= 5"`, and is
+    /// reported in `ParseError::expected` on failure.
+    fn pred<F>(self, label: impl Into<String>, f: F) -> Box<Pred<Self, F>>
+    where
+        Self: Sized + 'static,
+        F: Fn(&Self::Output) -> bool,
+    {
+        Box::new(Pred::new(self, label, f))
+    }
+}
+
+/// Lets a boxed parser (as returned by `.then()`, `.or()`, etc.) be chained
+/// into further fluent calls just like an unboxed one.
+impl<P: Parse + ?Sized> Parse for Box<P> {
+    type Output = P::Output;
+
+    fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError> {
+        (**self).parse(state)
+    }
+
+    fn id(&self) -> usize {
+        (**self).id()
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Self::Output, State), ParseError> {
+        (**self).parse_memo(state, memo)
+    }
+}
+
+/// Runs `parser` over the whole of `s`, failing if any input is left
+/// unconsumed at the end.
+pub fn parse_complete<P: Parse>(parser: &P, s: &str) -> Result<P::Output, ParseError> {
+    let (output, state) = parser.parse(&State::new(s.to_string(), 0))?;
+    if state.is_complete() {
+        Ok(output)
+    } else {
+        Err(ParseError::new(state.offset, vec!["end of input".to_string()]))
+    }
 }
 
 pub struct Lit {
     lit: String,
+    id: usize,
 }
 
 impl Lit {
     pub fn new(lit: String) -> Self {
-        Self { lit }
+        Self { lit, id: next_id() }
     }
 }
 
 impl Parse for Lit {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)> {
-        let peeked = state.peek(self.lit.len());
+    type Output = String;
+
+    fn parse(&self, state: &State) -> Result<(String, State), ParseError> {
+        let len = self.lit.graphemes(true).count();
+        let peeked = state.peek(len);
         if peeked == self.lit {
-            Some((vec![peeked], state.read(self.lit.len())))
+            Ok((peeked, state.read(len)))
         } else {
-            None
+            Err(ParseError::new(state.offset, vec![self.lit.clone()]))
         }
     }
+
+    fn id(&self) -> usize {
+        self.id
+    }
 }
 
 pub struct Char {
     re: Regex,
+    src: String,
+    id: usize,
 }
 
 impl Char {
     pub fn new(re: &str) -> Result<Self, regex::Error> {
         Ok(Self {
-            re: Regex::new(&format!("[{}]", re))?,
+            re: Regex::new(&format!("^[{}]$", re))?,
+            src: re.to_string(),
+            id: next_id(),
         })
     }
 }
 
 impl Parse for Char {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)> {
+    type Output = String;
+
+    fn parse(&self, state: &State) -> Result<(String, State), ParseError> {
         let peeked = state.peek(1);
         if self.re.is_match(&peeked) {
-            Some((vec![peeked], state.read(1)))
+            Ok((peeked, state.read(1)))
         } else {
-            None
+            Err(ParseError::new(state.offset, vec![self.src.clone()]))
         }
     }
+
+    fn id(&self) -> usize {
+        self.id
+    }
 }
 
-pub struct Seq {
-    seq: Vec<Box<dyn Parse>>,
+pub struct Seq<O> {
+    seq: Vec<Box<dyn Parse<Output = O>>>,
+    id: usize,
 }
 
-impl Seq {
-    pub fn new(seq: Vec<Box<dyn Parse>>) -> Self {
-        Self { seq }
+impl<O> Seq<O> {
+    pub fn new(seq: Vec<Box<dyn Parse<Output = O>>>) -> Self {
+        Self { seq, id: next_id() }
     }
 }
 
-impl Parse for Seq {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)> {
+impl<O: Clone + 'static> Parse for Seq<O> {
+    type Output = Vec<O>;
+
+    fn parse(&self, state: &State) -> Result<(Vec<O>, State), ParseError> {
         let mut current = state.clone();
         let mut results = Vec::new();
         for parse in self.seq.iter() {
@@ -96,67 +350,492 @@ impl Parse for Seq {
             results.push(res);
             current = state_next;
         }
-        Some((results.concat(), current))
+        Ok((results, current))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Vec<O>, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<Vec<O>>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let mut current = state.clone();
+        let mut results = Vec::new();
+        for parse in self.seq.iter() {
+            match parse.parse_memo(&current, memo) {
+                Ok((res, state_next)) => {
+                    results.push(res);
+                    current = state_next;
+                }
+                Err(err) => {
+                    memo.insert::<Vec<O>>(key, Err(err.clone()));
+                    return Err(err);
+                }
+            }
+        }
+        memo.insert(key, Ok((results.clone(), current.offset)));
+        Ok((results, current))
     }
 }
 
-pub struct Rep {
-    parse: Box<dyn Parse>,
+pub struct Rep<O> {
+    parse: Box<dyn Parse<Output = O>>,
     min: usize,
+    id: usize,
 }
 
-impl Rep {
-    pub fn new(parse: Box<dyn Parse>, min: usize) -> Self {
-        Self { parse, min }
+impl<O> Rep<O> {
+    pub fn new(parse: Box<dyn Parse<Output = O>>, min: usize) -> Self {
+        Self {
+            parse,
+            min,
+            id: next_id(),
+        }
     }
 }
 
-impl Parse for Rep {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)> {
+impl<O: Clone + 'static> Parse for Rep<O> {
+    type Output = Vec<O>;
+
+    fn parse(&self, state: &State) -> Result<(Vec<O>, State), ParseError> {
         let mut current = state.clone();
         let mut results = Vec::new();
         loop {
             match self.parse.parse(&current) {
-                Some((res, state_next)) => {
+                Ok((res, state_next)) => {
+                    // A zero-width-on-success parser (`Opt`, `Peek`, `Not`)
+                    // would otherwise never fail and loop forever here.
+                    let made_progress = state_next.offset != current.offset;
                     results.push(res);
                     current = state_next;
+                    if !made_progress {
+                        return if results.len() >= self.min {
+                            Ok((results, current))
+                        } else {
+                            Err(ParseError::new(current.offset, vec![format!("at least {} matches", self.min)]))
+                        };
+                    }
                 }
-                None => {
+                Err(err) => {
                     if results.len() >= self.min {
-                        return Some((results.concat(), current));
+                        return Ok((results, current));
                     } else {
-                        return None;
+                        return Err(err);
                     }
                 }
             }
         }
     }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Vec<O>, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<Vec<O>>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let mut current = state.clone();
+        let mut results = Vec::new();
+        let result = loop {
+            match self.parse.parse_memo(&current, memo) {
+                Ok((res, state_next)) => {
+                    // See the non-memoized `parse` above: without this,
+                    // a zero-width-on-success parser loops forever.
+                    let made_progress = state_next.offset != current.offset;
+                    results.push(res);
+                    current = state_next;
+                    if !made_progress {
+                        break if results.len() >= self.min {
+                            Ok((results.clone(), current.clone()))
+                        } else {
+                            Err(ParseError::new(current.offset, vec![format!("at least {} matches", self.min)]))
+                        };
+                    }
+                }
+                Err(err) => {
+                    break if results.len() >= self.min {
+                        Ok((results.clone(), current.clone()))
+                    } else {
+                        Err(err)
+                    };
+                }
+            }
+        };
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
 }
 
-pub struct Alt {
-    choices: Vec<Box<dyn Parse>>,
+pub struct Alt<O> {
+    choices: Vec<Box<dyn Parse<Output = O>>>,
+    id: usize,
 }
 
-impl Alt {
-    pub fn new(choices: Vec<Box<dyn Parse>>) -> Self {
-        Self { choices }
+impl<O> Alt<O> {
+    pub fn new(choices: Vec<Box<dyn Parse<Output = O>>>) -> Self {
+        Self {
+            choices,
+            id: next_id(),
+        }
     }
 }
 
-impl Parse for Alt {
-    fn parse(&self, state: &State) -> Option<(Vec<String>, State)> {
+impl<O: Clone + 'static> Parse for Alt<O> {
+    type Output = O;
+
+    fn parse(&self, state: &State) -> Result<(O, State), ParseError> {
+        let mut furthest: Option<ParseError> = None;
+        for parse in self.choices.iter() {
+            match parse.parse(state) {
+                Ok(res) => return Ok(res),
+                Err(err) => {
+                    furthest = Some(match furthest {
+                        Some(cur) => cur.furthest(err),
+                        None => err,
+                    });
+                }
+            }
+        }
+        Err(furthest.unwrap_or_else(|| ParseError::new(state.offset, vec![])))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(O, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<O>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let mut furthest: Option<ParseError> = None;
         for parse in self.choices.iter() {
-            let parsed = parse.parse(state);
-            if parsed.is_some() {
-                return parsed;
+            match parse.parse_memo(state, memo) {
+                Ok(res) => {
+                    memo.insert(key, Ok((res.0.clone(), res.1.offset)));
+                    return Ok(res);
+                }
+                Err(err) => {
+                    furthest = Some(match furthest {
+                        Some(cur) => cur.furthest(err),
+                        None => err,
+                    });
+                }
             }
         }
-        None
+        let err = furthest.unwrap_or_else(|| ParseError::new(state.offset, vec![]));
+        memo.insert::<O>(key, Err(err.clone()));
+        Err(err)
+    }
+}
+
+/// Wraps a parser and applies `f` to its output on success, letting a flat
+/// match (e.g. a `Seq` of digit chars) be collapsed into a domain type
+/// (e.g. an `i64`).
+pub struct Map<P, F, O> {
+    parse: P,
+    f: F,
+    id: usize,
+    _output: std::marker::PhantomData<O>,
+}
+
+impl<P, F, O> Map<P, F, O>
+where
+    P: Parse,
+    F: Fn(P::Output) -> O,
+{
+    pub fn new(parse: P, f: F) -> Self {
+        Self {
+            parse,
+            f,
+            id: next_id(),
+            _output: std::marker::PhantomData,
+        }
     }
 }
 
-// TODO: A node mapping closure parameter.
-// TODO: Some nicer high level wrappers.
+impl<P, F, O> Parse for Map<P, F, O>
+where
+    P: Parse,
+    F: Fn(P::Output) -> O,
+    O: Clone + 'static,
+{
+    type Output = O;
+
+    fn parse(&self, state: &State) -> Result<(O, State), ParseError> {
+        let (res, state_next) = self.parse.parse(state)?;
+        Ok(((self.f)(res), state_next))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(O, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<O>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = self
+            .parse
+            .parse_memo(state, memo)
+            .map(|(res, state_next)| ((self.f)(res), state_next));
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
+
+/// Sequences two differently-typed parsers, producing both outputs as a
+/// tuple. Built by `.then()`; unlike `Seq`, `first` and `second` need not
+/// share an `Output` type.
+pub struct Then<P1, P2> {
+    first: P1,
+    second: P2,
+    id: usize,
+}
+
+impl<P1, P2> Then<P1, P2> {
+    pub fn new(first: P1, second: P2) -> Self {
+        Self {
+            first,
+            second,
+            id: next_id(),
+        }
+    }
+}
+
+impl<P1, P2> Parse for Then<P1, P2>
+where
+    P1: Parse,
+    P2: Parse,
+{
+    type Output = (P1::Output, P2::Output);
+
+    fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError> {
+        let (a, state_next) = self.first.parse(state)?;
+        let (b, state_next) = self.second.parse(&state_next)?;
+        Ok(((a, b), state_next))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Self::Output, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<Self::Output>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = (|| {
+            let (a, state_next) = self.first.parse_memo(state, memo)?;
+            let (b, state_next) = self.second.parse_memo(&state_next, memo)?;
+            Ok(((a, b), state_next))
+        })();
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
+
+/// Makes a parser optional: succeeds with `Some(output)` when `P` matches,
+/// or `None` without consuming input when it doesn't. Built by `.opt()`.
+pub struct Opt<P> {
+    parse: P,
+    id: usize,
+}
+
+impl<P> Opt<P> {
+    pub fn new(parse: P) -> Self {
+        Self {
+            parse,
+            id: next_id(),
+        }
+    }
+}
+
+impl<P: Parse> Parse for Opt<P> {
+    type Output = Option<P::Output>;
+
+    fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError> {
+        match self.parse.parse(state) {
+            Ok((res, state_next)) => Ok((Some(res), state_next)),
+            Err(_) => Ok((None, state.clone())),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Self::Output, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<Self::Output>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = match self.parse.parse_memo(state, memo) {
+            Ok((res, state_next)) => Ok((Some(res), state_next)),
+            Err(_) => Ok((None, state.clone())),
+        };
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
+
+/// Zero-width positive lookahead: succeeds with `()` and the *original*
+/// un-advanced `State` when `P` matches, otherwise fails with `P`'s error.
+pub struct Peek<P> {
+    parse: P,
+    id: usize,
+}
+
+impl<P> Peek<P> {
+    pub fn new(parse: P) -> Self {
+        Self {
+            parse,
+            id: next_id(),
+        }
+    }
+}
+
+impl<P: Parse> Parse for Peek<P> {
+    type Output = ();
+
+    fn parse(&self, state: &State) -> Result<((), State), ParseError> {
+        self.parse.parse(state).map(|_| ((), state.clone()))
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<((), State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<()>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = self.parse.parse_memo(state, memo).map(|_| ((), state.clone()));
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
+
+/// Zero-width negative lookahead: succeeds with `()` and an unchanged
+/// `State` exactly when `P` fails, and fails when `P` matches. `label`
+/// describes what was forbidden, e.g. `"</"`, so the `ParseError` raised on
+/// failure still carries a useful `expected` set.
+pub struct Not<P> {
+    parse: P,
+    label: String,
+    id: usize,
+}
+
+impl<P> Not<P> {
+    pub fn new(parse: P, label: impl Into<String>) -> Self {
+        Self {
+            parse,
+            label: label.into(),
+            id: next_id(),
+        }
+    }
+}
+
+impl<P: Parse> Parse for Not<P> {
+    type Output = ();
+
+    fn parse(&self, state: &State) -> Result<((), State), ParseError> {
+        match self.parse.parse(state) {
+            Ok(_) => Err(ParseError::new(state.offset, vec![format!("not {}", self.label)])),
+            Err(_) => Ok(((), state.clone())),
+        }
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<((), State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<()>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = match self.parse.parse_memo(state, memo) {
+            Ok(_) => Err(ParseError::new(state.offset, vec![format!("not {}", self.label)])),
+            Err(_) => Ok(((), state.clone())),
+        };
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
+
+/// Runs `P` then keeps the result only if `f` returns true for it,
+/// otherwise fails at `P`'s start offset. `label` describes what `f`
+/// requires, e.g. `"digit >= 5"`, so the `ParseError` raised on failure
+/// still carries a useful `expected` set.
+pub struct Pred<P, F> {
+    parse: P,
+    f: F,
+    label: String,
+    id: usize,
+}
+
+impl<P, F> Pred<P, F>
+where
+    P: Parse,
+    F: Fn(&P::Output) -> bool,
+{
+    pub fn new(parse: P, label: impl Into<String>, f: F) -> Self {
+        Self {
+            parse,
+            f,
+            label: label.into(),
+            id: next_id(),
+        }
+    }
+}
+
+impl<P, F> Parse for Pred<P, F>
+where
+    P: Parse,
+    F: Fn(&P::Output) -> bool,
+{
+    type Output = P::Output;
+
+    fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError> {
+        let (res, state_next) = self.parse.parse(state)?;
+        if (self.f)(&res) {
+            Ok((res, state_next))
+        } else {
+            Err(ParseError::new(state.offset, vec![self.label.clone()]))
+        }
+    }
+
+    fn id(&self) -> usize {
+        self.id
+    }
+
+    fn parse_memo(&self, state: &State, memo: &mut Memo) -> Result<(Self::Output, State), ParseError> {
+        let key = (self.id, state.offset);
+        if let Some(cached) = memo.get::<Self::Output>(key) {
+            return cached.map(|(output, end)| (output, state.at_offset(end)));
+        }
+        let result = match self.parse.parse_memo(state, memo) {
+            Ok((res, state_next)) => {
+                if (self.f)(&res) {
+                    Ok((res, state_next))
+                } else {
+                    Err(ParseError::new(state.offset, vec![self.label.clone()]))
+                }
+            }
+            Err(err) => Err(err),
+        };
+        memo.insert(key, result.clone().map(|(output, next)| (output, next.offset)));
+        result
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -167,12 +846,12 @@ mod tests {
         let hello = Lit::new("hello".to_string());
         assert_eq!(
             hello.parse(&State::new("hellofoobar".to_string(), 0)),
-            Some((
-                vec!["hello".to_string()],
-                State::new("hellofoobar".to_string(), 5)
-            ))
+            Ok(("hello".to_string(), State::new("hellofoobar".to_string(), 5)))
+        );
+        assert_eq!(
+            hello.parse(&State::new("hellfoobar".to_string(), 0)),
+            Err(ParseError::new(0, vec!["hello".to_string()])),
         );
-        assert_eq!(hello.parse(&State::new("hellfoobar".to_string(), 0)), None,);
     }
 
     #[test]
@@ -180,9 +859,22 @@ mod tests {
         let digits = Char::new("0-9").unwrap();
         assert_eq!(
             digits.parse(&State::new("7a".to_string(), 0)),
-            Some((vec!["7".to_string()], State::new("7a".to_string(), 1)))
+            Ok(("7".to_string(), State::new("7a".to_string(), 1)))
+        );
+        assert_eq!(
+            digits.parse(&State::new("a".to_string(), 0)),
+            Err(ParseError::new(0, vec!["0-9".to_string()])),
+        );
+
+        // "e\u{0301}" (base 'e' + combining acute accent) is a single
+        // grapheme cluster containing the codepoint 'e', but the cluster as
+        // a whole is an accented letter, not a plain ASCII one, so it must
+        // not match `a-z`.
+        let letters = Char::new("a-z").unwrap();
+        assert_eq!(
+            letters.parse(&State::new("e\u{0301}".to_string(), 0)),
+            Err(ParseError::new(0, vec!["a-z".to_string()])),
         );
-        assert_eq!(digits.parse(&State::new("a".to_string(), 0)), None);
     }
 
     #[test]
@@ -194,12 +886,15 @@ mod tests {
         ]);
         assert_eq!(
             cookie.parse(&State::new("5 cookie".to_string(), 0)),
-            Some((
+            Ok((
                 vec!["5".to_string(), " ".to_string(), "cookie".to_string()],
                 State::new("5 cookie".to_string(), 8)
             ))
         );
-        assert_eq!(cookie.parse(&State::new("5xcookie".to_string(), 0)), None);
+        assert_eq!(
+            cookie.parse(&State::new("5xcookie".to_string(), 0)),
+            Err(ParseError::new(1, vec![" ".to_string()])),
+        );
     }
 
     #[test]
@@ -207,19 +902,22 @@ mod tests {
         let two_or_more = Rep::new(Box::new(Char::new("g").unwrap()), 2);
         assert_eq!(
             two_or_more.parse(&State::new("gg".to_string(), 0)),
-            Some((
+            Ok((
                 vec!["g".to_string(), "g".to_string()],
                 State::new("gg".to_string(), 2)
             )),
         );
         assert_eq!(
             two_or_more.parse(&State::new("ggg".to_string(), 0)),
-            Some((
+            Ok((
                 vec!["g".to_string(), "g".to_string(), "g".to_string()],
                 State::new("ggg".to_string(), 3)
             )),
         );
-        assert_eq!(two_or_more.parse(&State::new("g".to_string(), 0)), None);
+        assert_eq!(
+            two_or_more.parse(&State::new("g".to_string(), 0)),
+            Err(ParseError::new(1, vec!["g".to_string()])),
+        );
     }
 
     #[test]
@@ -230,12 +928,247 @@ mod tests {
         ]);
         assert_eq!(
             either.parse(&State::new("foo".to_string(), 0)),
-            Some((vec!["foo".to_string()], State::new("foo".to_string(), 3))),
+            Ok(("foo".to_string(), State::new("foo".to_string(), 3))),
         );
         assert_eq!(
             either.parse(&State::new("bar".to_string(), 0)),
-            Some((vec!["bar".to_string()], State::new("bar".to_string(), 3))),
+            Ok(("bar".to_string(), State::new("bar".to_string(), 3))),
+        );
+        assert_eq!(
+            either.parse(&State::new("lol".to_string(), 0)),
+            Err(ParseError::new(0, vec!["foo".to_string(), "bar".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        let digit = Char::new("0-9").unwrap();
+        let digit_value = Map::new(digit, |s: String| s.parse::<i64>().unwrap());
+        assert_eq!(
+            digit_value.parse(&State::new("7a".to_string(), 0)),
+            Ok((7, State::new("7a".to_string(), 1)))
+        );
+        assert_eq!(
+            digit_value.parse(&State::new("a".to_string(), 0)),
+            Err(ParseError::new(0, vec!["0-9".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_parse_complete() {
+        let hello = Lit::new("hello".to_string());
+        assert_eq!(parse_complete(&hello, "hello"), Ok("hello".to_string()));
+        assert_eq!(
+            parse_complete(&hello, "hello world"),
+            Err(ParseError::new(5, vec!["end of input".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_grapheme_clusters() {
+        // "👨‍👩‍👧" is a single grapheme cluster made up of several code points,
+        // so a byte-indexed State would have panicked slicing into it.
+        let family = Lit::new("👨‍👩‍👧".to_string());
+        let state = State::new("👨‍👩‍👧hello".to_string(), 0);
+        assert_eq!(
+            family.parse(&state),
+            Ok(("👨‍👩‍👧".to_string(), State::new("👨‍👩‍👧hello".to_string(), 1)))
+        );
+
+        let hello = Lit::new("hello".to_string());
+        let (_, after_family) = family.parse(&state).unwrap();
+        assert_eq!(
+            hello.parse(&after_family),
+            Ok(("hello".to_string(), State::new("👨‍👩‍👧hello".to_string(), 6)))
+        );
+        assert!(hello.parse(&after_family).unwrap().1.is_complete());
+    }
+
+    #[test]
+    fn test_parse_memo() {
+        let cookie = Seq::new(vec![
+            Box::new(Char::new("0-9").unwrap()),
+            Box::new(Char::new(" ").unwrap()),
+            Box::new(Lit::new("cookie".to_string())),
+        ]);
+        let mut memo = Memo::new();
+        let state = State::new("5 cookie".to_string(), 0);
+        let expected = Ok((
+            vec!["5".to_string(), " ".to_string(), "cookie".to_string()],
+            State::new("5 cookie".to_string(), 8),
+        ));
+        // Re-parsing the same parser at the same offset should hit the
+        // cache and return an identical result.
+        assert_eq!(cookie.parse_memo(&state, &mut memo), expected);
+        assert_eq!(cookie.parse_memo(&state, &mut memo), expected);
+
+        assert_eq!(
+            cookie.parse_memo(&State::new("5xcookie".to_string(), 0), &mut Memo::new()),
+            Err(ParseError::new(1, vec![" ".to_string()])),
+        );
+    }
+
+    /// Wraps a parser and counts how many times `parse` actually runs, so
+    /// tests can tell a cache hit from a cache miss instead of just
+    /// comparing outputs (which would pass even if nothing were cached).
+    struct CountingParser<P> {
+        inner: P,
+        descents: std::cell::Cell<usize>,
+        id: usize,
+    }
+
+    impl<P> CountingParser<P> {
+        fn new(inner: P) -> Self {
+            Self {
+                inner,
+                descents: std::cell::Cell::new(0),
+                id: next_id(),
+            }
+        }
+    }
+
+    impl<P: Parse> Parse for CountingParser<P> {
+        type Output = P::Output;
+
+        fn parse(&self, state: &State) -> Result<(Self::Output, State), ParseError> {
+            self.descents.set(self.descents.get() + 1);
+            self.inner.parse(state)
+        }
+
+        fn id(&self) -> usize {
+            self.id
+        }
+    }
+
+    #[test]
+    fn test_parse_memo_avoids_redescending() {
+        // Demonstrates the cache actually skips re-descending, not just
+        // that re-parsing is deterministic: calling `parse_memo` on the
+        // same parser at the same offset three times should only run
+        // `parse` once.
+        let digit = CountingParser::new(Char::new("0-9").unwrap());
+        let mut memo = Memo::new();
+        let state = State::new("5".to_string(), 0);
+
+        digit.parse_memo(&state, &mut memo).unwrap();
+        digit.parse_memo(&state, &mut memo).unwrap();
+        digit.parse_memo(&state, &mut memo).unwrap();
+
+        assert_eq!(digit.descents.get(), 1);
+    }
+
+    #[test]
+    fn test_fluent_builders() {
+        let digit = Char::new("0-9").unwrap();
+        let number = digit
+            .many1()
+            .map(|digits: Vec<String>| digits.concat().parse::<i64>().unwrap());
+        let word = Char::new("a-z").unwrap().many1().map(|cs: Vec<String>| cs.concat());
+        let greeting = number.then(Lit::new(" ".to_string())).then(word);
+
+        assert_eq!(
+            greeting.parse(&State::new("42 hello".to_string(), 0)),
+            Ok((((42, " ".to_string()), "hello".to_string()), State::new("42 hello".to_string(), 8)))
+        );
+
+        let foo_or_bar = Lit::new("foo".to_string()).or(Lit::new("bar".to_string()));
+        assert_eq!(
+            foo_or_bar.parse(&State::new("bar".to_string(), 0)),
+            Ok(("bar".to_string(), State::new("bar".to_string(), 3))),
+        );
+
+        let maybe_digit = Char::new("0-9").unwrap().opt();
+        assert_eq!(
+            maybe_digit.parse(&State::new("a".to_string(), 0)),
+            Ok((None, State::new("a".to_string(), 0))),
+        );
+        assert_eq!(
+            maybe_digit.parse(&State::new("7".to_string(), 0)),
+            Ok((Some("7".to_string()), State::new("7".to_string(), 1))),
+        );
+    }
+
+    #[test]
+    fn test_peek() {
+        // Only consumes "foo" if it's followed by a colon, without
+        // consuming the colon itself.
+        let foo_before_colon = Lit::new("foo".to_string()).then(Lit::new(":".to_string()).peek());
+        assert_eq!(
+            foo_before_colon.parse(&State::new("foo:bar".to_string(), 0)),
+            Ok((("foo".to_string(), ()), State::new("foo:bar".to_string(), 3))),
+        );
+        assert_eq!(
+            foo_before_colon.parse(&State::new("foobar".to_string(), 0)),
+            Err(ParseError::new(3, vec![":".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_not() {
+        // Consumes a letter as long as it isn't the start of a closing tag.
+        let not_close_tag = Lit::new("</".to_string())
+            .not("</")
+            .then(Char::new("a-z").unwrap());
+        assert_eq!(
+            not_close_tag.parse(&State::new("a".to_string(), 0)),
+            Ok((((), "a".to_string()), State::new("a".to_string(), 1))),
+        );
+        assert_eq!(
+            not_close_tag.parse(&State::new("</p>".to_string(), 0)),
+            Err(ParseError::new(0, vec!["not </".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_pred() {
+        let big_digit = Char::new("0-9")
+            .unwrap()
+            .map(|s: String| s.parse::<i64>().unwrap())
+            .pred("digit >= 5", |n: &i64| *n >= 5);
+        assert_eq!(
+            big_digit.parse(&State::new("7".to_string(), 0)),
+            Ok((7, State::new("7".to_string(), 1))),
+        );
+        assert_eq!(
+            big_digit.parse(&State::new("3".to_string(), 0)),
+            Err(ParseError::new(0, vec!["digit >= 5".to_string()])),
+        );
+    }
+
+    #[test]
+    fn test_rep_zero_width_progress_guard() {
+        // `Opt`/`Peek`/`Not` can succeed without consuming input (`Opt` and
+        // `Not` when their inner parser doesn't match, `Peek` when it does),
+        // so `Rep` must stop after one iteration instead of looping forever.
+        let opt_many = Char::new("z").unwrap().opt().many();
+        assert_eq!(
+            opt_many.parse(&State::new("abc".to_string(), 0)),
+            Ok((vec![None], State::new("abc".to_string(), 0))),
+        );
+
+        let peek_many = Lit::new("a".to_string()).peek().many();
+        assert_eq!(
+            peek_many.parse(&State::new("abc".to_string(), 0)),
+            Ok((vec![()], State::new("abc".to_string(), 0))),
+        );
+
+        let not_many = Lit::new("z".to_string()).not("z").many();
+        assert_eq!(
+            not_many.parse(&State::new("abc".to_string(), 0)),
+            Ok((vec![()], State::new("abc".to_string(), 0))),
+        );
+    }
+
+    #[test]
+    fn test_rep_zero_width_respects_min() {
+        // `Rep::new` is a public constructor, so a caller can ask for a
+        // `min` greater than the single zero-width result a non-consuming
+        // inner parser can ever produce. That must fail, not silently
+        // succeed with fewer than `min` results.
+        let rep = Rep::new(Box::new(Char::new("z").unwrap().opt()), 2);
+        assert_eq!(
+            rep.parse(&State::new("abc".to_string(), 0)),
+            Err(ParseError::new(0, vec!["at least 2 matches".to_string()])),
         );
-        assert_eq!(either.parse(&State::new("lol".to_string(), 0)), None);
     }
 }